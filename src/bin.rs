@@ -46,8 +46,196 @@ enum ChunkWriterMessage {
 /// Edge: offset in the input and sha256 sum of the chunk
 type Edge = (usize, Vec<u8>);
 
-struct Chunker {
+/// A content-defined chunking algorithm
+trait Chunker {
+    /// Bytes (from the start of `buf`) up to the next chunk boundary, if any
+    fn find_chunk_edge(&mut self, buf : &[u8]) -> Option<usize>;
+
+    /// Reset internal state after a chunk boundary was consumed
+    fn reset(&mut self);
+}
+
+/// `Chunker` backed by `rollsum`'s `bup`/rsync rolling checksum
+struct BupChunker {
     roll : rollsum::Bup,
+}
+
+impl BupChunker {
+    fn new() -> Self {
+        BupChunker { roll: rollsum::Bup::new() }
+    }
+}
+
+impl Chunker for BupChunker {
+    fn find_chunk_edge(&mut self, buf : &[u8]) -> Option<usize> {
+        self.roll.find_chunk_edge(buf)
+    }
+
+    fn reset(&mut self) {
+        self.roll = rollsum::Bup::new();
+    }
+}
+
+/// Minimum chunk size; bytes below this are never tested for a cutpoint
+const FASTCDC_MIN_SIZE : usize = 2 * 1024;
+/// Maximum chunk size; a cut is forced here
+const FASTCDC_MAX_SIZE : usize = 64 * 1024;
+/// Target average chunk size; switches between `FASTCDC_MASK_S` and `FASTCDC_MASK_L`
+const FASTCDC_AVG_SIZE : usize = 8 * 1024;
+/// Mask used below `FASTCDC_AVG_SIZE`; 15 one-bits, harder to match
+const FASTCDC_MASK_S : u64 = 0x90a0102180207242;
+/// Mask used at or above `FASTCDC_AVG_SIZE`; 11 one-bits, easier to match
+const FASTCDC_MASK_L : u64 = 0x008001524004c408;
+
+/// Gear table for `FastCdcChunker`'s rolling fingerprint, one entry per input byte
+static FASTCDC_GEAR : [u64; 256] = [
+    0xaac4528bd0a58d4c, 0xc64dc50648048a3a, 0x3ef8d1b5c5e4c571, 0xa1800877b0df661e,
+    0x93499447b1851682, 0x5ddcbd00f92ebb57, 0xb89b285df272a946, 0x334a71fd82f50acb,
+    0x5ba197fe264d21e7, 0x29a6863bb8fb07cc, 0x86a6a37dc2a27424, 0x51403921d5a60ad1,
+    0xe181d710ab18dd5c, 0xfb775c01a2c7cc17, 0xdff346b9accc0f27, 0xb413e7cbd5a80075,
+    0xc7cad98a2025ee41, 0x1b555351082740eb, 0xbc6fe5c465363a30, 0x38c6986c9c4c02e3,
+    0x91267da2de2c779b, 0x1f44d124f853aa34, 0x9d6990997e23ddbd, 0xaa59524158d36632,
+    0xabd4293b25842f58, 0x0c629858806a93ea, 0x672d2eb040b443c7, 0x0a3b472bde375ef0,
+    0x245d9ddd0c365bfd, 0x4f8a0c8f7c96170c, 0x08c8222f8872eea1, 0x953da3503a9d6f36,
+    0x89ff9a3a71f9b044, 0x3eac2d8fbebcaf55, 0x142278d3631748aa, 0x1173b4247526a0b6,
+    0x07a08a9895c66783, 0x78aad2822b1c097b, 0x1c6146cc846d3ced, 0x576a6c18dd32260d,
+    0x52868872ddc5e359, 0x6ce51fc2a8e84928, 0xb29b27ad49ce8f65, 0xf98ed61a64c3e64e,
+    0xafd14b51327e38c1, 0x6a5a35c184ff0606, 0x22b527b312f4a6cf, 0x4c1295bbd3f0de93,
+    0x5c478fc31a3bcb10, 0x5e027f37e1f0092e, 0xb1696f6badfb21dc, 0x1177cec58a604d0c,
+    0xc86425c12f5e4004, 0x00f12603ab2bbe21, 0x1e2b0826e2435119, 0xcb6d86fae3333481,
+    0x448351c0b9a42ac5, 0x36d530c538a831c9, 0x21f8d3bae62b9b0e, 0xd2f01ce7a2557b2e,
+    0xd22dab640133c22e, 0x92103a0f663e19cd, 0xe8171e27a5debe09, 0x59e5b956fcaea777,
+    0x0c11b1a44d11952c, 0x6420e0c63e6e9188, 0x8d8f15b4da80db71, 0xb2f0f68a118e0cc9,
+    0xbc611754e63fd478, 0x6a1229e883b1468a, 0x41a86134624ea50c, 0xe7c19ae7bb097c2e,
+    0x127ca01f93c18c5c, 0x43cb4ad96901d04d, 0xf860c934a33cdb5f, 0x0d3449b4bddc6125,
+    0x82d15cdcdab6bfa8, 0x7b2a14ae071f1ebc, 0xb9481f96327ffb02, 0xe54918c93877f63f,
+    0x20848f591fad53de, 0x1070823b7e737090, 0x2839a75de9f7d479, 0x860819d306218e00,
+    0xa0a0d2dc296e8082, 0x3dcff1d01539a50c, 0x2af745fd25b3c10c, 0x70802605f81758e6,
+    0xac472ce1c5fb4bfd, 0x1f06595a70994696, 0xaabbcc8e31a23fbb, 0xcac72a8910140661,
+    0x3119f186582176b2, 0xf8607c1b035e17f0, 0xd9fe1baa637b626a, 0x2c53f92af442dd2a,
+    0x7c5f43e5d767c562, 0x0e9735ce880009fb, 0x08216bb0a571a599, 0xf3370843a392b596,
+    0x81c0c52631d5d1de, 0x1bead2be472ccfd6, 0x10b30cad9b665e96, 0xa77ad8cd917a2dc8,
+    0x5afa5821d9e8b555, 0xad920e23cdc0bad9, 0xb9a454c6f28294e1, 0x51cdec4cd0f72a90,
+    0xfef6dab03a29066d, 0x59be9afdb9f8e671, 0x8200fca551d537ca, 0x34ba3dd422f250c3,
+    0x6efe88679bdf5f37, 0xe8e2622648a0eeb0, 0x38dc40e6cae170c0, 0xcd81b7e5efb1772a,
+    0x0bf35b48b42223ce, 0x4f7eec35fe431976, 0x6904d93fffe26fb9, 0x9e5eb22f61a19cb8,
+    0xe45eefa183d048e1, 0xb9ceec717d84424a, 0xe572894307c6f2ca, 0x43aed1b92c84bbc3,
+    0x1d151571ecdf0655, 0x1e2cda43717cd4fd, 0x030d2d47021f53ea, 0x6dbb0053f0f2108b,
+    0x82a795f3aff9379d, 0x01195d4bd7ddc0a2, 0x5f3848c00349cd31, 0x7711c3e23cc6bfc5,
+    0x3b5b29531c96eeab, 0x671d6a143dcb1107, 0xcbf8a974714623e2, 0x57a62f16dbf97d72,
+    0x35c397ecb7e176dd, 0xeb6144295ffa4451, 0x092834ebafd1ab2b, 0x01ed47a6ec7929f4,
+    0x83f0cb6afda2c2e1, 0x6053b3fccc8c3eb2, 0xaa9d91f92f945228, 0xdcc321649c8c005b,
+    0xa4b4377f6264897e, 0x0ef7d081cf87f5d0, 0x0ffa0a2d6e03cd09, 0x050a6b82c0540e61,
+    0xe04d4857b59bf2f9, 0x4533017aaeb88ff8, 0x6fdcedc4ba75e55e, 0xf5e632709ac1ea99,
+    0x1078c947a8a77a24, 0x86d45f7a2bf2cc0f, 0x1a476399f7d70f0b, 0x17409c1f3d266294,
+    0x0cb4e2dd0564d975, 0x4501df99e33d1c9a, 0x5491550e00f1595e, 0xa870f67d72114a0e,
+    0x090d7affc8e7e33d, 0x23be3fff34b46483, 0xc4793eec29813014, 0xbb69a7afe9e5c260,
+    0xe4247527020e7cd3, 0x790fcb0a63d2c888, 0x272d6fac837047ee, 0x972971d87c72e900,
+    0x177965065fae4d01, 0x26c285120247134c, 0x96b849373ccc2130, 0x1538dee9639b414b,
+    0x6393721d7f5fe99b, 0x29b284e716f654e3, 0x3dc0420add79c64e, 0x8ed7d98c5cc29bbc,
+    0x071c051995882376, 0xaab00439fa88d6ea, 0xb3aea29b9bdec1f2, 0x50a4ab54e31da587,
+    0xdb0cbddda0df6a06, 0x841da1d44e52f794, 0xef1662e1aa0c1223, 0x245c0345f17a03eb,
+    0x4c99e0973e33f0c9, 0xa0103686e22b03dc, 0x456112e94f32c64d, 0x0bd320ce80770116,
+    0xc10908095b8387ba, 0xc64eaf6cad10c33d, 0xb4cfe8cb38695f97, 0xd8b1c46b58548dc3,
+    0x7b7194d6e75bb73c, 0xdda6ae163c2e2e8d, 0xa673492b949246c7, 0xf71a772d45f017af,
+    0x74f47d59ae8bda70, 0xb85214410115d5ce, 0x777235b2edfafcb8, 0x91bfeab17d07b5c3,
+    0x431dc2a5650486d4, 0x7167a9a4ee9c482d, 0x32eb8204c1982f56, 0xbe935346fe47ee7c,
+    0xf1f46797df4286d2, 0x6f50f5ad8f337c79, 0x201f1ecc8c9d6ad4, 0x35f3ec7e771faf1b,
+    0x598b7e0b1b8e7f84, 0x6451b3799d77a2f5, 0xf70c22301cf0094e, 0xe4f84053466cea34,
+    0xb6bc003bd1811b9c, 0x81b94c818731dade, 0xfbb3422aa53d3099, 0x458d5eb2e9145058,
+    0x68bda3fa08bc4dbb, 0x1fe7a7ea88eac188, 0x2863b8c85dc7cbde, 0xc921ec0eacecda32,
+    0xea3365bba9e9915d, 0x908a69edc8fce5c2, 0x52c0bbce64735f89, 0xa6c1f5a6f6aeb6ab,
+    0x86b9365838fa20e9, 0x6d2fa47037e81381, 0xf4d54aebe3cd7d35, 0xfbb23d296c076808,
+    0x275434199e5553ce, 0x858d377a58139209, 0x444b2e6aa4a43081, 0x8f25192bdc6d4da8,
+    0x29e6d5c5b49be554, 0x750cf452c7b20266, 0x6de68327e0548a72, 0xd0011f981f6ad861,
+    0x83b8e54813feb859, 0xe1bf5ad0257e069e, 0xbba1ae0bf9d144f2, 0xb12a6f24a6d5d24e,
+    0xd282a22ecc4f36b1, 0x00048e58d2b84154, 0x755da4714808382e, 0xc90c03a0b64b8506,
+    0x442a13a89a0954bf, 0x807e702a2aa88cbe, 0x853668ef97ff1f7b, 0x4565652043bb3265,
+    0x4f4feedb87794d7b, 0x07fcf70bdfbbc7eb, 0x4d8f4b53f4e74948, 0x083c3c655d5af738,
+    0xe069dc1459853a69, 0xaeb9462ec41ca65a, 0x3ecfb4d841c6e5f4, 0x6e24420dfba3c955,
+];
+
+/// `Chunker` implementing FastCDC: a per-byte Gear rolling hash with normalized chunking
+struct FastCdcChunker {
+    fp : u64,
+    bytes_since_edge : usize,
+}
+
+impl FastCdcChunker {
+    fn new() -> Self {
+        FastCdcChunker { fp: 0, bytes_since_edge: 0 }
+    }
+}
+
+impl Chunker for FastCdcChunker {
+    fn find_chunk_edge(&mut self, buf : &[u8]) -> Option<usize> {
+        for (i, &b) in buf.iter().enumerate() {
+            self.bytes_since_edge += 1;
+            self.fp = (self.fp << 1).wrapping_add(FASTCDC_GEAR[b as usize]);
+
+            if self.bytes_since_edge < FASTCDC_MIN_SIZE {
+                continue;
+            }
+
+            if self.bytes_since_edge >= FASTCDC_MAX_SIZE {
+                return Some(i + 1);
+            }
+
+            let mask = if self.bytes_since_edge < FASTCDC_AVG_SIZE {
+                FASTCDC_MASK_S
+            } else {
+                FASTCDC_MASK_L
+            };
+
+            if self.fp & mask == 0 {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    fn reset(&mut self) {
+        self.fp = 0;
+        self.bytes_since_edge = 0;
+    }
+}
+
+/// Chunking algorithm selectable through `--chunker` and recorded in repo metadata
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ChunkerType {
+    Bup,
+    FastCdc,
+}
+
+impl ChunkerType {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ChunkerType::Bup => "bup",
+            ChunkerType::FastCdc => "fastcdc",
+        }
+    }
+
+    fn new_chunker(&self) -> Box<Chunker> {
+        match *self {
+            ChunkerType::Bup => Box::new(BupChunker::new()),
+            ChunkerType::FastCdc => Box::new(FastCdcChunker::new()),
+        }
+    }
+}
+
+impl FromStr for ChunkerType {
+    type Err = ();
+    fn from_str(src: &str) -> Result<ChunkerType, ()> {
+        match src {
+            "bup" => Ok(ChunkerType::Bup),
+            "fastcdc" => Ok(ChunkerType::FastCdc),
+            _ => Err(()),
+        }
+    }
+}
+
+struct ChunkSplitter {
+    roll : Box<Chunker>,
     sha256 : sha2::Sha256,
     bytes_total : usize,
     bytes_chunk: usize,
@@ -56,10 +244,10 @@ struct Chunker {
     edges : Vec<Edge>,
 }
 
-impl Chunker {
-    pub fn new() -> Self {
-        Chunker {
-            roll: rollsum::Bup::new(),
+impl ChunkSplitter {
+    pub fn new(chunker_type : ChunkerType) -> Self {
+        ChunkSplitter {
+            roll: chunker_type.new_chunker(),
             sha256: sha2::Sha256::new(),
             bytes_total: 0,
             bytes_chunk: 0,
@@ -69,9 +257,7 @@ impl Chunker {
     }
 
     pub fn edge_found(&mut self, input_ofs : usize) {
-        debug!("found edge at {}; sum: {:x}",
-                 self.bytes_total,
-                 self.roll.digest());
+        debug!("found edge at {}", self.bytes_total);
 
         debug!("sha256 hash: {}",
                  self.sha256.result_str());
@@ -85,7 +271,7 @@ impl Chunker {
         self.bytes_chunk += 0;
 
         self.sha256.reset();
-        self.roll = rollsum::Bup::new();
+        self.roll.reset();
     }
 
     pub fn input(&mut self, buf : &[u8]) -> Vec<Edge> {
@@ -193,8 +379,9 @@ fn restore_data<W : Write+Send>(
 fn store_data<R : Read>(tx : mpsc::Sender<ChunkWriterMessage>,
                       mut reader : &mut R,
                       chunk_type : ChunkType,
+                      chunker_type : ChunkerType,
                       ) -> Vec<u8> {
-    let mut chunker = Chunker::new();
+    let mut chunker = ChunkSplitter::new(chunker_type);
 
     let mut index : Vec<u8> = vec!();
     loop {
@@ -221,7 +408,7 @@ fn store_data<R : Read>(tx : mpsc::Sender<ChunkWriterMessage>,
     tx.send(ChunkWriterMessage::Data(vec!(), edges, chunk_type)).unwrap();
 
     if index.len() > 32 {
-        store_data(tx, &mut io::Cursor::new(index), ChunkType::Index)
+        store_data(tx, &mut io::Cursor::new(index), ChunkType::Index, chunker_type)
     } else {
         index
     }
@@ -229,9 +416,9 @@ fn store_data<R : Read>(tx : mpsc::Sender<ChunkWriterMessage>,
 }
 
 /// Store stdio and return a digest
-fn store_stdio(tx : mpsc::Sender<ChunkWriterMessage>) -> Vec<u8> {
+fn store_stdio(tx : mpsc::Sender<ChunkWriterMessage>, chunker_type : ChunkerType) -> Vec<u8> {
     let mut stdin = io::stdin();
-    store_data(tx, &mut stdin, ChunkType::Data)
+    store_data(tx, &mut stdin, ChunkType::Data, chunker_type)
 }
 
 fn digest_to_path(digest : &[u8], chunk_type : ChunkType, options : &GlobalOptions) -> PathBuf {
@@ -344,6 +531,44 @@ fn load_sec_key_into_options(options : &mut GlobalOptions) {
     }
 }
 
+fn chunker_type_file_path(options : &GlobalOptions) -> PathBuf {
+    options.dst_dir.join("chunker")
+}
+
+/// Load the chunker algorithm a repo was saved with, if recorded and recognized
+fn load_chunker_type_into_options(options : &mut GlobalOptions) {
+    let path = chunker_type_file_path(options);
+
+    if let Ok(mut file) = fs::File::open(&path) {
+        let mut buf = String::new();
+        if file.read_to_string(&mut buf).is_ok() {
+            if let Ok(chunker_type) = ChunkerType::from_str(buf.trim()) {
+                options.chunker_type = chunker_type;
+            }
+        }
+    }
+}
+
+/// Record the chunker `save` is about to use, or verify it matches a previously recorded one
+fn save_chunker_type(options : &GlobalOptions) {
+    let path = chunker_type_file_path(options);
+
+    if path.exists() {
+        let mut file = fs::File::open(&path).unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        let recorded = ChunkerType::from_str(buf.trim()).unwrap();
+        if recorded != options.chunker_type {
+            printerrln!("Repo was saved with chunker `{}', can't save with `{}'",
+                        recorded.as_str(), options.chunker_type.as_str());
+            process::exit(-1);
+        }
+    } else {
+        let mut file = fs::File::create(path).unwrap();
+        file.write_all(options.chunker_type.as_str().as_bytes()).unwrap();
+    }
+}
+
 fn repo_init(options : &mut GlobalOptions) {
     fs::create_dir_all(&options.dst_dir).unwrap();
     let path = pub_key_file_path(options);
@@ -368,6 +593,7 @@ struct GlobalOptions {
     dst_dir : PathBuf,
     pub_key : Option<box_::PublicKey>,
     sec_key : Option<box_::SecretKey>,
+    chunker_type : ChunkerType,
 }
 
 enum Command {
@@ -396,6 +622,7 @@ fn main() {
         dst_dir: Path::new("backup").to_owned(),
         pub_key: None,
         sec_key: None,
+        chunker_type: ChunkerType::Bup,
     };
 
     let mut subcommand = Command::Help;
@@ -407,6 +634,9 @@ fn main() {
         ap.refer(&mut options.verbose)
             .add_option(&["-v", "--verbose"], StoreTrue,
                         "Be verbose");
+        ap.refer(&mut options.chunker_type)
+            .add_option(&["--chunker"], Store,
+                r#"Chunking algorithm to use on "save" (either "bup" or "fastcdc")"#);
         ap.refer(&mut subcommand)
             .add_argument("command", Store,
                 r#"Command to run (either "save" or "restore")"#);
@@ -425,9 +655,11 @@ fn main() {
         },
         Command::Save => {
             load_pub_key_into_options(&mut options);
+            save_chunker_type(&options);
+            let chunker_type = options.chunker_type;
             let chunk_writer_join = thread::spawn(move || chunk_writer(rx, &options));
 
-            let final_digest = store_stdio(tx.clone());
+            let final_digest = store_stdio(tx.clone(), chunker_type);
 
             println!("Stored as {}", final_digest.to_hex());
 
@@ -441,6 +673,8 @@ fn main() {
             }
             load_pub_key_into_options(&mut options);
             load_sec_key_into_options(&mut options);
+            load_chunker_type_into_options(&mut options);
+            debug!("repo chunker: {}", options.chunker_type.as_str());
 
             let digest = args[0].from_hex().unwrap();
             restore_data::<io::Stdout>(&digest, &mut io::stdout(), &options);